@@ -0,0 +1,133 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+/// A single include/exclude pattern: a glob by default, or a regex when prefixed with `re:`.
+/// Patterns are matched against paths relative to the tracked root.
+enum Pattern {
+    Glob(globset::GlobMatcher),
+    Regex(regex::Regex),
+}
+
+impl Pattern {
+    fn parse(raw: &str) -> anyhow::Result<Self> {
+        Ok(if let Some(re) = raw.strip_prefix("re:") {
+            Self::Regex(regex::Regex::new(re).with_context(|| format!("Invalid regex {raw:?}"))?)
+        } else {
+            let glob = raw.strip_prefix("glob:").unwrap_or(raw);
+            Self::Glob(
+                globset::Glob::new(glob)
+                    .with_context(|| format!("Invalid glob {raw:?}"))?
+                    .compile_matcher(),
+            )
+        })
+    }
+    fn is_match(&self, path: &Path) -> bool {
+        match self {
+            Self::Glob(matcher) => matcher.is_match(path),
+            Self::Regex(re) => path.to_str().map_or(false, |path| re.is_match(path)),
+        }
+    }
+}
+
+enum Section {
+    Include,
+    Exclude,
+}
+
+/// Include/exclude rules parsed from a `.mtime-rewind` config file. `%include` pulls in a
+/// shared rule file in place, and `%unset` removes a rule added earlier by its exact pattern
+/// text.
+#[derive(Default)]
+pub struct Matcher {
+    include: Vec<(String, Pattern)>,
+    exclude: Vec<(String, Pattern)>,
+}
+
+impl Matcher {
+    pub fn load(root: &Path) -> anyhow::Result<Self> {
+        let mut matcher = Self::default();
+        let path = root.join(".mtime-rewind");
+        if path.exists() {
+            let mut seen = HashSet::new();
+            matcher
+                .load_file(&path, &mut seen)
+                .with_context(|| format!("While parsing config file {:?}", path))?;
+        }
+        Ok(matcher)
+    }
+    /// `seen` tracks the canonicalized paths of config files currently being loaded along this
+    /// `%include` chain (i.e. this file's own ancestors), so a file that directly or
+    /// transitively includes itself is rejected instead of recursing forever. The path is
+    /// removed again once this file finishes loading, so the same file can still be `%include`d
+    /// from unrelated branches (e.g. two files sharing a common included file).
+    fn load_file(&mut self, path: &Path, seen: &mut HashSet<PathBuf>) -> anyhow::Result<()> {
+        let canonical = path
+            .canonicalize()
+            .with_context(|| format!("Could not resolve {:?}", path))?;
+        anyhow::ensure!(
+            seen.insert(canonical.clone()),
+            "Cyclic %include of {:?}",
+            path
+        );
+        let result = self.load_file_inner(path, seen);
+        seen.remove(&canonical);
+        result
+    }
+    fn load_file_inner(&mut self, path: &Path, seen: &mut HashSet<PathBuf>) -> anyhow::Result<()> {
+        let contents =
+            std::fs::read_to_string(path).with_context(|| format!("Could not read {:?}", path))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+
+        let mut section = None;
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if let Some(included) = line.strip_prefix("%include ") {
+                let included = dir.join(included.trim());
+                self.load_file(&included, seen)
+                    .with_context(|| format!("While including {:?}", included))?;
+                continue;
+            }
+            if let Some(pattern) = line.strip_prefix("%unset ") {
+                let pattern = pattern.trim();
+                self.include.retain(|(raw, _)| raw != pattern);
+                self.exclude.retain(|(raw, _)| raw != pattern);
+                continue;
+            }
+            match line {
+                "[include]" => {
+                    section = Some(Section::Include);
+                    continue;
+                }
+                "[exclude]" => {
+                    section = Some(Section::Exclude);
+                    continue;
+                }
+                _ => {}
+            }
+            let section = section
+                .as_ref()
+                .with_context(|| format!("Pattern {line:?} outside of an [include]/[exclude] section"))?;
+            let parsed = Pattern::parse(line)?;
+            match section {
+                Section::Include => self.include.push((line.to_string(), parsed)),
+                Section::Exclude => self.exclude.push((line.to_string(), parsed)),
+            }
+        }
+        Ok(())
+    }
+    /// Whether `path` (relative to the tracked root) should participate in hashing and
+    /// rewinding. An `[include]` match always wins, including over `default_excluded` (the
+    /// hardcoded hidden-entry/cache-folder rules), so it's the only way to bring one of those
+    /// back. Otherwise a path is excluded if `default_excluded` or an `[exclude]` rule matches.
+    pub fn is_included(&self, path: &Path, default_excluded: bool) -> bool {
+        if self.include.iter().any(|(_, p)| p.is_match(path)) {
+            return true;
+        }
+        !default_excluded && !self.exclude.iter().any(|(_, p)| p.is_match(path))
+    }
+}
@@ -4,9 +4,12 @@ use std::path::{Path, PathBuf};
 use anyhow::Context;
 use clap::Parser;
 use log::*;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use sha2::Digest;
 
+mod config;
+
 /// Rewind the mtime of files whose mtime advanced since the last execution without a content change.
 #[derive(Parser)]
 struct Flags {
@@ -14,77 +17,220 @@ struct Flags {
     /// Do not edit only mtime, only list the changes that would be made.
     #[clap(long)]
     dry: bool,
+    /// Digest algorithm used to fingerprint file contents.
+    #[clap(long, value_enum, default_value = "sha256")]
+    hash: HashAlgo,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Re-point the `.hashprint` store found at `root` at its current location: rewrite its
+    /// stored root, translate every entry's path accordingly, and drop entries for files that
+    /// no longer exist, so a directory relocation doesn't strand the recorded mtimes.
+    #[clap(alias = "rebuild")]
+    Rebase,
+}
+
+/// Digest algorithm used to hash file contents, stored alongside the data so a later run can
+/// detect a mismatch and recompute rather than comparing hashes produced by different algorithms.
+#[derive(clap::ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+enum HashAlgo {
+    Sha256,
+    Blake3,
 }
-#[derive(Serialize, Deserialize, Debug)]
+
+impl HashAlgo {
+    fn hash(self, mut file: impl std::io::Read) -> anyhow::Result<Vec<u8>> {
+        Ok(match self {
+            Self::Sha256 => {
+                let mut hasher = sha2::Sha256::new();
+                std::io::copy(&mut file, &mut hasher)?;
+                hasher.finalize().to_vec()
+            }
+            Self::Blake3 => {
+                let mut hasher = blake3::Hasher::new();
+                std::io::copy(&mut file, &mut hasher)?;
+                hasher.finalize().as_bytes().to_vec()
+            }
+        })
+    }
+}
+
+/// Coarsest mtime granularity we assume a filesystem may have. A recorded mtime within this
+/// much of the scan that recorded it cannot be trusted to reflect a subsequent same-tick edit.
+const MTIME_RESOLUTION: std::time::Duration = std::time::Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct Entry {
     hash: Vec<u8>,
     mtime: std::time::SystemTime,
+    size: u64,
+    /// Set when `mtime` was within [MTIME_RESOLUTION] of the scan that recorded it (or later
+    /// than that scan), so a later
+    /// edit landing in the same tick could leave the timestamp looking unchanged. Such entries
+    /// are never trusted by the size+mtime fast path and are always rehashed instead.
+    ambiguous: bool,
 }
 
 impl Entry {
-    fn from_file(filename: &Path) -> anyhow::Result<Self> {
-        let mut hasher = sha2::Sha256::new();
-        let file = std::fs::File::open(filename)?;
-        let mut file = std::io::BufReader::new(file);
-        std::io::copy(&mut file, &mut hasher)?;
-        let hash = hasher.finalize();
-
-        let meta = std::fs::metadata(filename)?;
-        Ok(Self {
-            hash: hash.to_vec(),
-            mtime: meta.modified()?,
-        })
+    fn is_ambiguous(mtime: std::time::SystemTime, now: std::time::SystemTime) -> bool {
+        now.duration_since(mtime)
+            .map_or(true, |age| age < MTIME_RESOLUTION)
+    }
+    /// Reads `filename`'s metadata, treating a vanished file as "skip it" rather than an error,
+    /// since it may simply have been deleted between the directory walk and this call.
+    fn metadata(filename: &Path) -> anyhow::Result<Option<std::fs::Metadata>> {
+        match std::fs::metadata(filename) {
+            Ok(meta) => Ok(Some(meta)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+    /// Hash `filename`, reusing `previous`'s hash if its size and mtime match the live file and
+    /// it is not marked ambiguous, since an untouched file cannot have acquired different
+    /// contents. Returns `Ok(None)` if
+    /// the file is deleted or replaced while this runs.
+    fn resolve(
+        filename: &Path,
+        previous: Option<&Entry>,
+        algo: HashAlgo,
+        now: std::time::SystemTime,
+    ) -> anyhow::Result<Option<Self>> {
+        let Some(meta) = Self::metadata(filename)? else {
+            return Ok(None);
+        };
+        let mtime = meta.modified()?;
+        let size = meta.len();
+        if let Some(previous) = previous {
+            if !previous.ambiguous && previous.size == size && previous.mtime == mtime {
+                return Ok(Some(Self {
+                    hash: previous.hash.clone(),
+                    mtime,
+                    size,
+                    ambiguous: Self::is_ambiguous(mtime, now),
+                }));
+            }
+        }
+        Self::from_file(filename, algo, now)
+    }
+    fn from_file(
+        filename: &Path,
+        algo: HashAlgo,
+        now: std::time::SystemTime,
+    ) -> anyhow::Result<Option<Self>> {
+        let Some(meta_before) = Self::metadata(filename)? else {
+            return Ok(None);
+        };
+        let mtime_before = meta_before.modified()?;
+
+        let file = match std::fs::File::open(filename) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let file = std::io::BufReader::new(file);
+        let hash = algo.hash(file)?;
+
+        let Some(meta_after) = Self::metadata(filename)? else {
+            return Ok(None);
+        };
+        let mtime_after = meta_after.modified()?;
+        if mtime_after != mtime_before {
+            // The file was replaced while we were hashing it: the hash we just computed may not
+            // match the mtime we'd store alongside it, so skip it for this run.
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            hash,
+            mtime: mtime_after,
+            size: meta_after.len(),
+            ambiguous: Self::is_ambiguous(mtime_after, now),
+        }))
     }
 }
 #[derive(Serialize, Deserialize)]
 struct Data {
     data: HashMap<PathBuf, Entry>,
     root: PathBuf,
+    hash_algo: HashAlgo,
 }
 impl Data {
-    fn compute(root: &Path) -> anyhow::Result<Self> {
+    fn compute(
+        root: &Path,
+        cached: Option<&Self>,
+        algo: HashAlgo,
+        matcher: &config::Matcher,
+    ) -> anyhow::Result<Self> {
         info!("Computing hashes...");
-        let files = walkdir::WalkDir::new(root)
+        let files: Vec<PathBuf> = walkdir::WalkDir::new(root)
             .min_depth(1)
             .into_iter()
-            // Skip hidden entries and cache folders (e.g. cargo's target fodlers)
+            // Skip hidden entries, cache folders (e.g. cargo's target fodlers), and anything
+            // excluded by the `.mtime-rewind` config file. Patterns are matched root-relative,
+            // and an explicit `[include]` rule can bring back a hidden entry or cache folder.
             .filter_entry(|e| {
-                !e.path().join("CACHEDIR.TAG").exists()
-                    && !e
-                        .path()
+                let hidden = e.path().join("CACHEDIR.TAG").exists()
+                    || e.path()
                         .file_name()
                         .and_then(|f| f.to_str())
-                        .map_or(false, |f| f.starts_with('.'))
+                        .map_or(false, |f| f.starts_with('.'));
+                let relative = e.path().strip_prefix(root).unwrap_or_else(|_| e.path());
+                matcher.is_included(relative, hidden)
             })
             .filter_map(|e| e.ok())
-            .filter(|e| e.metadata().map_or(false, |e| e.is_file()));
+            .filter(|e| e.metadata().map_or(false, |e| e.is_file()))
+            .map(|e| e.path().into())
+            .collect();
 
-        // Compute current hashes
-        let mut data = HashMap::default();
-        for entry in files {
-            data.insert(entry.path().into(), Entry::from_file(entry.path())?);
-        }
+        let now = std::time::SystemTime::now();
+        // Hash files concurrently, bounded by the available cores. Files whose size and mtime
+        // match a cached entry that is not ambiguous reuse its hash rather than being rehashed.
+        // Files deleted or
+        // replaced mid-scan are dropped instead of aborting the whole run.
+        let data: HashMap<PathBuf, Entry> = files
+            .into_par_iter()
+            .filter_map(|path| {
+                match Entry::resolve(&path, cached.and_then(|c| c.data.get(&path)), algo, now) {
+                    Ok(Some(entry)) => Some(Ok((path, entry))),
+                    Ok(None) => {
+                        debug!("{:?} vanished during the scan, skipping", path);
+                        None
+                    }
+                    Err(e) => Some(Err(e)),
+                }
+            })
+            .collect::<anyhow::Result<_>>()?;
         info!("Computed hashes for {} files", data.len());
         Ok(Self {
             data,
             root: root.into(),
+            hash_algo: algo,
         })
     }
     fn hashes_file(root: &Path) -> PathBuf {
         root.join(".hashprint")
     }
-    fn load_cached(root: &Path) -> anyhow::Result<Self> {
+    /// Load the store found at `root`, without requiring its recorded root to match `root`.
+    /// Used by [`Self::rebase`], for which a mismatch is exactly the expected, stale state.
+    fn load_cached_unchecked(root: &Path) -> anyhow::Result<Self> {
         info!("Loading cached state...");
         let cached =
             std::fs::read(Self::hashes_file(root)).context("Could not open hash file.")?;
         let cached: Self = bincode::deserialize(&cached)?;
+        info!("Loaded hashes for {:?} files", cached.data.len());
+        Ok(cached)
+    }
+    fn load_cached(root: &Path) -> anyhow::Result<Self> {
+        let cached = Self::load_cached_unchecked(root)?;
         anyhow::ensure!(
             cached.root == root,
             "Mismatching roots found: {:?} vs {:?}",
             cached.root,
             root
         );
-        info!("Loaded hashes for {:?} files", cached.data.len());
         Ok(cached)
     }
     fn save(&self) -> anyhow::Result<()> {
@@ -93,57 +239,164 @@ impl Data {
         info!("Wrote {:?}", output);
         Ok(())
     }
+    /// Re-point the store found at `root` at `root` itself: translate every stored path from
+    /// the old root to the new one, drop entries for files that no longer exist there, and
+    /// re-serialize in place. This lets recorded mtimes survive a directory relocation. When
+    /// `dry` is set, only logs what would change and leaves the on-disk store untouched.
+    fn rebase(root: &Path, dry: bool) -> anyhow::Result<()> {
+        let mut data = Self::load_cached_unchecked(root)?;
+        let old_root = std::mem::replace(&mut data.root, root.into());
+        if old_root != root {
+            info!("Rebasing stored root from {:?} to {:?}", old_root, root);
+        }
+        data.data = data
+            .data
+            .into_iter()
+            .map(|(path, entry)| {
+                let relative = path.strip_prefix(&old_root).unwrap_or(&path);
+                (root.join(relative), entry)
+            })
+            .collect();
+
+        let before = data.data.len();
+        data.data.retain(|path, _| path.exists());
+        info!(
+            "Dropped {} entries for files that no longer exist",
+            before - data.data.len()
+        );
+
+        if dry {
+            warn!("Dry mode, not applying changes");
+            return Ok(());
+        }
+        data.save()
+    }
+}
+/// Set `path`'s mtime, treating it having vanished as "skip it" rather than a fatal error, since
+/// it may have been deleted or replaced since the scan that observed it. Returns whether the
+/// mtime was actually applied.
+fn rewind_mtime(path: &Path, mtime: std::time::SystemTime) -> anyhow::Result<bool> {
+    match filetime::set_file_mtime(path, filetime::FileTime::from_system_time(mtime)) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            info!("{:?} no longer exists, skipping", path);
+            Ok(false)
+        }
+        Err(e) => Err(e.into()),
+    }
 }
 fn main() -> anyhow::Result<()> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
     let args = Flags::parse();
 
-    let live = Data::compute(&args.root)?;
+    if matches!(args.command, Some(Command::Rebase)) {
+        Data::rebase(&args.root, args.dry)?;
+        info!("Done");
+        return Ok(());
+    }
+
+    let stored = if Data::hashes_file(&args.root).exists() {
+        Some(Data::load_cached(&args.root)?)
+    } else {
+        None
+    };
+    // A store built with a different digest can't be compared against; ignore it and recompute.
+    let stored = match stored {
+        Some(stored) if stored.hash_algo != args.hash => {
+            warn!(
+                "Stored hashes use {:?}, requested {:?}; recomputing from scratch",
+                stored.hash_algo, args.hash
+            );
+            None
+        }
+        stored => stored,
+    };
+
+    let matcher = config::Matcher::load(&args.root)?;
+    let live = Data::compute(&args.root, stored.as_ref(), args.hash, &matcher)?;
 
-    if !Data::hashes_file(&args.root).exists() {
+    let Some(stored) = stored else {
         info!("Writing hashes for the first time...");
         live.save()?;
-    } else {
-        info!("Restoring modification times for unchanged files...");
-        let stored = Data::load_cached(&args.root)?;
-
-        let mut edited = HashMap::<PathBuf, Entry>::default();
-        for (f, stored) in stored.data {
-            if let Some(live) = live.data.get(&f) {
-                debug!("{:?}: {:?} (live) vs {:?} (stored)", f, live, stored);
-                // Find files whose contents haven't changed, yet the mtime is set to later than
-                // on the previous run
-                if live.mtime > stored.mtime {
-                    if live.hash != stored.hash {
-                        // Legitimate mtime increase
-                        info!("{:?} was actually modified", f);
-                    } else {
-                        info!(
-                            "Rewinding {:?} from {:?} to {:?} as its contents did not change",
-                            f, live.mtime, stored.mtime
-                        );
-                        if args.dry {
-                            warn!("Dry mode, not applying changes");
-                        } else {
-                            filetime::set_file_mtime(
-                                &f,
-                                filetime::FileTime::from_system_time(stored.mtime),
-                            )?;
-                            edited.insert(f, stored);
-                        }
+        info!("Done");
+        return Ok(());
+    };
+
+    info!("Restoring modification times for unchanged files...");
+
+    let mut edited = HashMap::<PathBuf, Entry>::default();
+
+    // Detect moves/renames: a path that only exists live, whose content hash matches a stored
+    // entry whose path has disappeared, carries over that entry's mtime. Content hashing alone
+    // can't tell a move from two unrelated files that happen to share content (most commonly
+    // empty files), so we only act when the hash uniquely identifies one removed and one added
+    // path, and never for zero-byte files.
+    {
+        let mut removed_by_hash = HashMap::<&[u8], Vec<&Entry>>::new();
+        for (path, entry) in &stored.data {
+            if !live.data.contains_key(path) {
+                removed_by_hash.entry(entry.hash.as_slice()).or_default().push(entry);
+            }
+        }
+        let mut added_by_hash = HashMap::<&[u8], Vec<&PathBuf>>::new();
+        for (path, entry) in &live.data {
+            if !stored.data.contains_key(path) {
+                added_by_hash.entry(entry.hash.as_slice()).or_default().push(path);
+            }
+        }
+        for (hash, removed) in &removed_by_hash {
+            let [old_entry] = removed.as_slice() else {
+                continue; // ambiguous: several removed files shared this hash
+            };
+            if old_entry.size == 0 {
+                continue; // too many unrelated files share the empty-file hash to be meaningful
+            }
+            let Some([path]) = added_by_hash.get(hash).map(Vec::as_slice) else {
+                continue; // no added path, or several: can't tell which one moved
+            };
+            info!(
+                "{:?} looks like a move with unchanged contents, rewinding mtime to {:?}",
+                path, old_entry.mtime
+            );
+            if args.dry {
+                warn!("Dry mode, not applying changes");
+            } else if rewind_mtime(path, old_entry.mtime)? {
+                edited.insert((*path).clone(), (*old_entry).clone());
+            }
+        }
+    }
+
+    for (f, stored) in stored.data {
+        if let Some(live) = live.data.get(&f) {
+            debug!("{:?}: {:?} (live) vs {:?} (stored)", f, live, stored);
+            // Find files whose contents haven't changed, yet the mtime is set to later than
+            // on the previous run
+            if live.mtime > stored.mtime {
+                if live.hash != stored.hash {
+                    // Legitimate mtime increase
+                    info!("{:?} was actually modified", f);
+                } else {
+                    info!(
+                        "Rewinding {:?} from {:?} to {:?} as its contents did not change",
+                        f, live.mtime, stored.mtime
+                    );
+                    if args.dry {
+                        warn!("Dry mode, not applying changes");
+                    } else if rewind_mtime(&f, stored.mtime)? {
+                        edited.insert(f, stored);
                     }
                 }
             }
         }
+    }
 
-        info!("{} files rewinded", edited.len());
-        // Apply the new state before saving
-        let mut live = live;
-        live.data.extend(edited);
-        if !args.dry {
-            info!("Saving the new state...");
-            live.save()?;
-        }
+    info!("{} files rewinded", edited.len());
+    // Apply the new state before saving
+    let mut live = live;
+    live.data.extend(edited);
+    if !args.dry {
+        info!("Saving the new state...");
+        live.save()?;
     }
     info!("Done");
     Ok(())
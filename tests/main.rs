@@ -1,15 +1,21 @@
+use std::ffi::OsStr;
 use std::path::Path;
 
-fn exec(root: impl AsRef<Path>) -> anyhow::Result<()> {
+fn exec_args<I, S>(args: I) -> anyhow::Result<()>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
     let bin = std::env!("CARGO_BIN_EXE_mtime-rewind");
-    let status = std::process::Command::new(bin)
-        .arg(root.as_ref())
-        .spawn()?
-        .wait()?;
+    let status = std::process::Command::new(bin).args(args).spawn()?.wait()?;
     anyhow::ensure!(status.success());
     Ok(())
 }
 
+fn exec(root: impl AsRef<Path>) -> anyhow::Result<()> {
+    exec_args([root.as_ref()])
+}
+
 fn touch(path: &Path) -> anyhow::Result<()> {
     anyhow::ensure!(std::process::Command::new("touch")
         .arg(path)
@@ -21,6 +27,12 @@ fn touch(path: &Path) -> anyhow::Result<()> {
 fn mtime(path: &Path) -> anyhow::Result<std::time::SystemTime> {
     Ok(std::fs::metadata(path)?.modified()?)
 }
+fn set_mtime(path: &Path, mtime: std::time::SystemTime) -> anyhow::Result<()> {
+    Ok(filetime::set_file_mtime(
+        path,
+        filetime::FileTime::from_system_time(mtime),
+    )?)
+}
 
 #[test]
 fn test() -> anyhow::Result<()> {
@@ -57,3 +69,305 @@ fn test() -> anyhow::Result<()> {
     assert_eq!(mtime_b2, mtime(&b)?);
     Ok(())
 }
+
+#[test]
+fn test_exclude_config() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let dir_path = dir.path();
+
+    let kept = dir_path.join("kept.txt");
+    let vendor_dir = dir_path.join("vendor");
+    std::fs::create_dir(&vendor_dir)?;
+    let excluded = vendor_dir.join("excluded.txt");
+
+    std::fs::write(&kept, "kept")?;
+    std::fs::write(&excluded, "excluded")?;
+    std::fs::write(dir_path.join(".mtime-rewind"), "[exclude]\nvendor/**\n")?;
+
+    let mtime_kept = mtime(&kept)?;
+
+    exec(&dir)?;
+    assert_eq!(mtime_kept, mtime(&kept)?);
+
+    touch(&kept)?;
+    touch(&excluded)?;
+    let mtime_excluded_touched = mtime(&excluded)?;
+    assert_ne!(mtime_kept, mtime(&kept)?); // sanity: touch actually bumped it
+
+    exec(&dir)?;
+    // kept.txt is tracked and its content is unchanged, so its mtime is rewound
+    assert_eq!(mtime_kept, mtime(&kept)?);
+    // excluded.txt is never tracked, so the mtime left by touch is untouched
+    assert_eq!(mtime_excluded_touched, mtime(&excluded)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_include_overrides_exclude() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let dir_path = dir.path();
+
+    let vendor_dir = dir_path.join("vendor");
+    std::fs::create_dir(&vendor_dir)?;
+    let excluded = vendor_dir.join("excluded.txt");
+    let kept = vendor_dir.join("keep-me.txt");
+
+    std::fs::write(&excluded, "excluded")?;
+    std::fs::write(&kept, "kept")?;
+    std::fs::write(
+        dir_path.join(".mtime-rewind"),
+        "[exclude]\nvendor/**\n[include]\nvendor/keep-me.txt\n",
+    )?;
+    let mtime_kept = mtime(&kept)?;
+
+    exec(&dir)?;
+    assert_eq!(mtime_kept, mtime(&kept)?);
+
+    touch(&excluded)?;
+    touch(&kept)?;
+    let mtime_excluded_touched = mtime(&excluded)?;
+    assert_ne!(mtime_kept, mtime(&kept)?); // sanity: touch actually bumped it
+
+    exec(&dir)?;
+    // vendor/keep-me.txt matches an [include] rule, which wins over the [exclude] rule that
+    // would otherwise drop all of vendor/, so it's tracked and rewound...
+    assert_eq!(mtime_kept, mtime(&kept)?);
+    // ... but vendor/excluded.txt isn't covered by [include], so [exclude] still applies to it
+    assert_eq!(mtime_excluded_touched, mtime(&excluded)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_unset_config() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let dir_path = dir.path();
+
+    let vendor_dir = dir_path.join("vendor");
+    std::fs::create_dir(&vendor_dir)?;
+    let tracked = vendor_dir.join("file.txt");
+    std::fs::write(&tracked, "content")?;
+
+    std::fs::write(dir_path.join("shared.conf"), "[exclude]\nvendor/**\n")?;
+    std::fs::write(
+        dir_path.join(".mtime-rewind"),
+        "%include shared.conf\n%unset vendor/**\n",
+    )?;
+    let mtime_tracked = mtime(&tracked)?;
+
+    exec(&dir)?;
+    assert_eq!(mtime_tracked, mtime(&tracked)?);
+
+    touch(&tracked)?;
+    assert_ne!(mtime_tracked, mtime(&tracked)?); // sanity: touch actually bumped it
+
+    exec(&dir)?;
+    // %unset removed the vendor/** exclude rule pulled in by %include, so the file is tracked
+    // and rewound as if the rule had never been there.
+    assert_eq!(mtime_tracked, mtime(&tracked)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_move_carries_mtime() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let dir_path = dir.path();
+
+    let original = dir_path.join("a");
+    std::fs::write(&original, "unique content for the move test")?;
+    let mtime_original = mtime(&original)?;
+
+    exec(&dir)?;
+
+    std::fs::remove_file(&original)?;
+    let moved = dir_path.join("a-moved");
+    std::fs::write(&moved, "unique content for the move test")?;
+    assert_ne!(mtime_original, mtime(&moved)?); // sanity: the new path starts with a fresh mtime
+
+    exec(&dir)?;
+    // "a-moved" has the exact content that used to live at "a", so its mtime is rewound to what
+    // "a" had, rather than being treated as a brand new file.
+    assert_eq!(mtime_original, mtime(&moved)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_ambiguous_move_not_rewound() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let dir_path = dir.path();
+
+    // Two stored files share the same content, so a removed-by-hash lookup for that hash finds
+    // two entries: which one (if either) the new path continues is ambiguous.
+    let a = dir_path.join("a");
+    let b = dir_path.join("b");
+    std::fs::write(&a, "duplicated content")?;
+    std::fs::write(&b, "duplicated content")?;
+
+    exec(&dir)?;
+
+    std::fs::remove_file(&a)?;
+    std::fs::remove_file(&b)?;
+    let added = dir_path.join("added");
+    std::fs::write(&added, "duplicated content")?;
+    let mtime_added = mtime(&added)?;
+
+    exec(&dir)?;
+    // Neither removed entry uniquely identifies "added", so its freshly-written mtime is left
+    // alone instead of being rewound to either "a"'s or "b"'s.
+    assert_eq!(mtime_added, mtime(&added)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_empty_file_move_not_rewound() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let dir_path = dir.path();
+
+    let original = dir_path.join("empty");
+    std::fs::write(&original, "")?;
+
+    exec(&dir)?;
+
+    std::fs::remove_file(&original)?;
+    let moved = dir_path.join("empty-moved");
+    std::fs::write(&moved, "")?;
+    let mtime_moved = mtime(&moved)?;
+
+    exec(&dir)?;
+    // Too many unrelated empty files could share this hash for a match to mean anything, so
+    // zero-byte files are never treated as moves.
+    assert_eq!(mtime_moved, mtime(&moved)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_hash_algo_switch_recomputes() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let dir_path = dir.path();
+    let a = dir_path.join("a");
+
+    std::fs::write(&a, "a")?;
+    exec(&dir)?; // builds the store with the default sha256
+
+    touch(&a)?;
+    // The stored hashes were sha256, so switching to blake3 is a mismatch: the store is
+    // recomputed from scratch rather than compared against, so this bumped mtime is not
+    // rewound yet.
+    exec_args([dir_path.as_os_str(), OsStr::new("--hash"), OsStr::new("blake3")])?;
+    let mtime_after_rebuild = mtime(&a)?;
+
+    touch(&a)?;
+    assert_ne!(mtime_after_rebuild, mtime(&a)?); // sanity: touch actually bumped it again
+
+    // Now both runs agree on blake3, so an unchanged-content bump is rewound as usual.
+    exec_args([dir_path.as_os_str(), OsStr::new("--hash"), OsStr::new("blake3")])?;
+    assert_eq!(mtime_after_rebuild, mtime(&a)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_ambiguous_mtime_forces_rehash() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let dir_path = dir.path();
+    let a = dir_path.join("a");
+
+    std::fs::write(&a, "aaaa")?;
+
+    // The file was just created, so the entry `exec` records for it is within
+    // MTIME_RESOLUTION of the scan that recorded it, i.e. "ambiguous".
+    exec(&dir)?;
+    let mtime_recorded = mtime(&a)?;
+
+    // Same-second edit: the content changes but we pin the mtime back to the value the first
+    // run recorded, simulating a filesystem whose clock granularity can't tell the two instants
+    // apart.
+    std::fs::write(&a, "bbbb")?;
+    set_mtime(&a, mtime_recorded)?;
+
+    exec(&dir)?;
+    // mtime didn't (apparently) advance, so nothing is rewound this run either way ...
+    assert_eq!(mtime_recorded, mtime(&a)?);
+
+    // ... but a real, later mtime bump with unchanged ("bbbb") content should only be rewound
+    // back to `mtime_recorded`. If the ambiguous entry above had been fast-pathed, the hash
+    // saved for it would still be "aaaa"'s, this run would see a hash mismatch, wrongly call it
+    // "actually modified", and leave the bumped mtime in place instead of rewinding it.
+    let later = mtime_recorded + std::time::Duration::from_secs(60);
+    set_mtime(&a, later)?;
+    exec(&dir)?;
+    assert_eq!(mtime_recorded, mtime(&a)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_deleted_file_does_not_abort_run() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let dir_path = dir.path();
+
+    let vanishing = dir_path.join("vanishing");
+    let kept = dir_path.join("kept");
+    std::fs::write(&vanishing, "here for now")?;
+    std::fs::write(&kept, "kept")?;
+    let mtime_kept = mtime(&kept)?;
+
+    exec(&dir)?;
+
+    touch(&kept)?;
+    assert_ne!(mtime_kept, mtime(&kept)?); // sanity: touch actually bumped it
+
+    // Removed right before the next scan, so the walk never lists it and its stored entry has
+    // no live counterpart. (A removal during the scan itself is what `Entry::metadata`,
+    // `Entry::from_file` and `rewind_mtime` treat as "skip it"; this test does not reproduce
+    // that race.)
+    std::fs::remove_file(&vanishing)?;
+
+    exec(&dir)?;
+    // The run still completes and still rewinds the file that's actually still there.
+    assert_eq!(mtime_kept, mtime(&kept)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_rebase() -> anyhow::Result<()> {
+    let base = tempfile::tempdir()?;
+    let old_root = base.path().join("old");
+    std::fs::create_dir(&old_root)?;
+
+    let a = old_root.join("a");
+    std::fs::write(&a, "a")?;
+    let mtime_a = mtime(&a)?;
+
+    exec(&old_root)?;
+
+    // Simulate the tracked tree being relocated on disk.
+    let new_root = base.path().join("new");
+    std::fs::rename(&old_root, &new_root)?;
+
+    let hashprint = new_root.join(".hashprint");
+    let stored_before_dry_run = std::fs::read(&hashprint)?;
+    exec_args([
+        new_root.as_os_str(),
+        OsStr::new("--dry"),
+        OsStr::new("rebase"),
+    ])?;
+    // --dry only lists what rebase would change, it doesn't touch the store on disk.
+    assert_eq!(stored_before_dry_run, std::fs::read(&hashprint)?);
+
+    exec_args([new_root.as_os_str(), OsStr::new("rebase")])?;
+
+    // A normal run against the new location recognizes the rebased store instead of failing on
+    // a root mismatch, and still rewinds the carried-over mtime.
+    touch(&new_root.join("a"))?;
+    exec(&new_root)?;
+    assert_eq!(mtime_a, mtime(&new_root.join("a"))?);
+
+    Ok(())
+}